@@ -1,22 +1,30 @@
 use std::str::FromStr;
-use std::{env, thread};
-use std::time::{Duration, SystemTime};
+use std::env;
+use std::time::{Duration, Instant, SystemTime};
 use std::process;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Receiver};
+use std::sync::atomic::{AtomicI64, Ordering};
+use tokio::sync::Semaphore;
 use base64;
 use getopts::Options;
 use mime::Mime;
-use hyper::body::HttpBody;
+use std::io::Read;
 use hyper::{Body, Client, Method, Request};
+use flate2::read::GzDecoder;
 use hyper::client::connect::HttpConnector;
 use hyper::header::{HeaderName, HeaderValue};
+use hyper_proxy::{Intercept, Proxy, ProxyConnector};
+
+// connector stack shared by every worker: the proxy connector wraps the plain
+// HttpConnector, so requests either tunnel through the upstream proxy or pass
+// straight through when no `-x` was given.
+type BoomClient = Client<ProxyConnector<HttpConnector>>;
 
 const N_DEFAULT: i32 = 200;
 const C_DEFAULT: i32 = 50;
 
 mod report;
-use report::Report;
+use report::{OutputFormat, Report};
 
 #[derive(Clone)]
 struct BoomOption {
@@ -24,49 +32,66 @@ struct BoomOption {
     num_requests: i32,
     method: Method,
     url: String,
-    body: String,
+    body: Vec<u8>,
     username: String,
     password: String,
     proxy_host: String,
     proxy_port: u16,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    http2: bool,
+    h2c: bool,
+    duration: Option<Duration>,
+    headers: Vec<(HeaderName, HeaderValue)>,
     keepalive: bool,
     compress: bool,
     mime: Mime,
 }
 
-struct WorkerOption {
-    opts: BoomOption,
-    report: Arc<Mutex<Report>>,
-}
+fn get_request(options: &BoomOption) -> BoomClient {
+    let mut connector = HttpConnector::new();
+    connector.set_connect_timeout(options.connect_timeout);
 
-fn get_request(options: &BoomOption) -> Client<HttpConnector> {
-    // TODO: support proxy and timeout
+    let proxy_connector = if options.proxy_host.is_empty() {
+        ProxyConnector::new(connector).expect("build proxy connector")
+    } else {
+        let proxy_uri = format!("http://{}:{}", options.proxy_host, options.proxy_port)
+            .parse()
+            .expect("invalid proxy uri");
+        let proxy = Proxy::new(Intercept::All, proxy_uri);
+        ProxyConnector::from_proxy(connector, proxy).expect("build proxy connector")
+    };
 
-    // let mut client = if options.proxy_host.is_empty() {
-    //     Client::new()
-    // } else {
-    //     Client::with_http_proxy(options.proxy_host.to_owned(), options.proxy_port)
-    // };
-    let client = Client::new();
-    // let timeout: Option<Duration> = Some(Duration::new(1, 0));
-    // client.set_connect_timeout(timeout);
-    return client;
+    let mut builder = Client::builder();
+    if options.http2 || options.h2c {
+        // This client only owns a plaintext `HttpConnector`, so there is no
+        // TLS/ALPN layer to negotiate h2 over https. Both `--http2` and
+        // `--h2c` therefore drive HTTP/2 with prior knowledge over the
+        // plaintext connection (`--http2` is an alias for `--h2c` here; see
+        // the https rejection in `main`), multiplexing streams over a small
+        // pool instead of one connection per request.
+        builder.http2_only(true);
+        builder.pool_max_idle_per_host(options.concurrency as usize);
+    }
+    builder.build(proxy_connector)
 }
 
 // one request
-async fn b(client: &Arc<Client<HttpConnector>>, options: BoomOption, report: Arc<Mutex<Report>>) -> bool {
+async fn b(client: &Arc<BoomClient>, options: BoomOption, report: Arc<Mutex<Report>>) -> bool {
     let request_body = if options.body.is_empty() {
         Body::empty()
     } else {
         Body::from(options.body.clone())
-    };
+    };  // Body::from(Vec<u8>) reuses the buffer, so binary bodies pass through
     let mut request = Request::builder()
         .method(options.method)
         .uri(options.url.as_str())
         .body(request_body)
         .unwrap();
     request.headers_mut().insert(HeaderName::from_static("user-agent"), HeaderValue::from_static("boom-rust"));
-    if !options.keepalive {
+    // `connection` is a connection-specific header and is illegal in HTTP/2
+    // (RFC 7540 §8.1.2.2), so only send it on HTTP/1.1.
+    if !options.keepalive && !options.http2 && !options.h2c {
         request.headers_mut().insert(HeaderName::from_static("connection"), HeaderValue::from_static("close"));
     }
     request.headers_mut().insert(HeaderName::from_static("content-type"), HeaderValue::from_str(options.mime.as_ref()).unwrap());
@@ -77,58 +102,110 @@ async fn b(client: &Arc<Client<HttpConnector>>, options: BoomOption, report: Arc
         let b64 = base64::encode(format!("{}:{}", options.username, options.password));
         request.headers_mut().insert(HeaderName::from_static("authorization"), HeaderValue::from_str(format!("Basic {}", b64).as_str()).unwrap());
     }
+    // user-supplied headers come last so they can override any of the built-ins
+    // above (user-agent, authorization, host, ...).
+    for (name, value) in options.headers.iter() {
+        request.headers_mut().insert(name.clone(), value.clone());
+    }
 
     let t1 = SystemTime::now();
-    let res = client.request(request).await.unwrap();
+    let result = match options.timeout {
+        Some(d) => match tokio::time::timeout(d, client.request(request)).await {
+            Ok(r) => r.map_err(|_| ()),
+            Err(_) => Err(()), // elapsed
+        },
+        None => client.request(request).await.map_err(|_| ()),
+    };
+    let res = match result {
+        Ok(res) => res,
+        Err(_) => {
+            // connection error or timeout: record as the synthetic status 0
+            // bucket instead of aborting the whole run.
+            let mut r = report.lock().unwrap();
+            let status_num = (*r).status_num.entry(0).or_insert(0);
+            *status_num += 1;
+            return false;
+        }
+    };
     let t2 = SystemTime::now();
     let duration = t2.duration_since(t1).unwrap();
-    let diff = duration.subsec_micros() as f32;
+    // use the full elapsed time: `subsec_micros` dropped whole seconds, so any
+    // request slower than 1s was recorded as a tiny fraction.
+    let micros = duration.as_micros() as u64;
+    let diff = duration.as_secs_f64() as f32 * 1_000_000.;
+
+    let status = res.status().as_u16();
+    let version = format!("{:?}", res.version());
+    let encoding = res
+        .headers()
+        .get(hyper::header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.trim().to_ascii_lowercase());
 
     {
         let mut r = report.lock().unwrap();
         let millisec = diff / 1000.;
         (*r).time_total += millisec;
         (*r).req_num += 1;
-        (*r).results.push((res.status().as_u16(), millisec));
+        (*r).results.push((status, millisec));
+        (*r).hist.record(micros);
+        let v = (*r).version_num.entry(version).or_insert(0);
+        *v += 1;
     }
 
-    if res.status().as_u16() != 200 {
+    // drain the body so we measure the real on-wire byte count instead of a
+    // header size hint, and decode it when the server compressed the response.
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap_or_default();
+    let compressed = body.len() as i64;
+    let decoded = match encoding.as_deref() {
+        Some("gzip") => {
+            let mut buf = Vec::new();
+            match GzDecoder::new(&body[..]).read_to_end(&mut buf) {
+                Ok(_) => buf.len() as i64,
+                Err(_) => compressed,
+            }
+        }
+        Some("br") => {
+            let mut buf = Vec::new();
+            match brotli::Decompressor::new(&body[..], 4096).read_to_end(&mut buf) {
+                Ok(_) => buf.len() as i64,
+                Err(_) => compressed,
+            }
+        }
+        _ => compressed,
+    };
+
+    {
         let mut r = report.lock().unwrap();
-        let status_num = (*r).status_num.entry(res.status().as_u16()).or_insert(0);
-        *status_num += 1;
-        return false;
+        (*r).compressed_total += compressed;
+        (*r).decoded_total += decoded;
     }
 
-    let content_len = match res.body().size_hint().upper() {
-        Some(v) => v,
-        None => 1025,  // TODO: error handling
-    };
-    {
+    if status != 200 {
         let mut r = report.lock().unwrap();
-        (*r).size_total += content_len as i64;
+        let status_num = (*r).status_num.entry(status).or_insert(0);
+        *status_num += 1;
+        return false;
     }
 
     let mut r = report.lock().unwrap();
+    (*r).size_total += compressed;
     let status_num = (*r).status_num.entry(200).or_insert(0);
     *status_num += 1;
     return true;
 }
 
-// exec actions
-async fn exec_boom(client: &Arc<Client<HttpConnector>>, options: BoomOption, report: Arc<Mutex<Report>>) {
-    Some(b(client, options, report).await);
-}
-
-async fn exec_worker(client: &Arc<Client<HttpConnector>>, rx: Receiver<Option<WorkerOption>>) {
-    loop {
-        match rx.recv().expect("rx.recv() error:") {
-            Some(wconf) => {
-                exec_boom(client, wconf.opts, wconf.report).await;
-            }
-            None => {
-                break;
-            }
-        }
+// parse a duration such as "30s", "500ms" or "2m" for the -z flag
+fn parse_duration(v: &str) -> Option<Duration> {
+    let v = v.trim();
+    if let Some(n) = v.strip_suffix("ms") {
+        n.parse::<u64>().ok().map(Duration::from_millis)
+    } else if let Some(n) = v.strip_suffix('s') {
+        n.parse::<f64>().ok().map(Duration::from_secs_f64)
+    } else if let Some(n) = v.strip_suffix('m') {
+        n.parse::<f64>().ok().map(|m| Duration::from_secs_f64(m * 60.))
+    } else {
+        v.parse::<f64>().ok().map(Duration::from_secs_f64)
     }
 }
 
@@ -145,9 +222,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     opts.optopt("c", "concurrency", "concurrency", "C");
     opts.optopt("m", "method", "HTTP method (GET, POST, PUT, DELETE, HEAD, OPTIONS)", "METHOD");
     opts.optopt("d", "data", "HTTP request body data", "DATA");
+    opts.optopt("D", "body-file", "read the HTTP request body from a file", "FILE");
+    opts.optmulti("H", "header", "custom request header 'Name: Value' (repeatable)", "HEADER");
     opts.optopt("T", "", "Content-type, defaults to \"text/html\".", "ContentType");
     opts.optopt("a", "", "use basic authentication", "USERNAME:PASSWORD");
     opts.optopt("x", "", "HTTP proxy address as host:port", "PROXY_HOST:PROXY_PORT");
+    opts.optopt("", "connect-timeout", "per-request connection timeout in seconds", "SECONDS");
+    opts.optopt("", "timeout", "per-request timeout in seconds", "SECONDS");
+    opts.optopt("z", "", "duration of the run (e.g. 30s); overrides -n", "DURATION");
+    opts.optopt("o", "", "output format: text (default), json, csv", "FORMAT");
+    opts.optopt("", "output", "write the report to FILE instead of stdout", "FILE");
+    opts.optflag("", "http2", "use HTTP/2 over plaintext (h2c, prior knowledge); alias of --h2c");
+    opts.optflag("", "h2c", "use HTTP/2 over plaintext (prior knowledge)");
     opts.optflag("", "disable-compress", "Disable compress");
     opts.optflag("", "disable-keepalive", "Disable keep-alive");
     let matches = match opts.parse(&args[1..]) {
@@ -176,9 +262,53 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         Some(v) => v.to_uppercase(),
         None => "GET".to_string(),
     };
-    let body_v = match matches.opt_str("d") {
-        Some(v) => v.to_string(),
-        None => "".to_string(),
+    // `-D FILE` reads the body from disk once (as raw bytes, so binary bodies
+    // work) and is reused across every request; it takes precedence over an
+    // inline `-d`.
+    let body_v: Vec<u8> = match matches.opt_str("D") {
+        Some(path) => match std::fs::read(&path) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("cannot read body file {}: {}\n", path, e);
+                print_usage(&opts);
+                process::exit(1);
+            }
+        },
+        None => match matches.opt_str("d") {
+            Some(v) => v.into_bytes(),
+            None => Vec::new(),
+        },
+    };
+    let headers = {
+        let mut parsed: Vec<(HeaderName, HeaderValue)> = Vec::new();
+        for h in matches.opt_strs("H") {
+            let (name, value) = match h.split_once(':') {
+                Some((n, v)) => (n.trim(), v.trim()),
+                None => {
+                    println!("invalid header: {}\n", h);
+                    print_usage(&opts);
+                    process::exit(1);
+                }
+            };
+            let name = match HeaderName::from_bytes(name.as_bytes()) {
+                Ok(n) => n,
+                Err(_) => {
+                    println!("invalid header name: {}\n", h);
+                    print_usage(&opts);
+                    process::exit(1);
+                }
+            };
+            let value = match HeaderValue::from_str(value) {
+                Ok(v) => v,
+                Err(_) => {
+                    println!("invalid header value: {}\n", h);
+                    print_usage(&opts);
+                    process::exit(1);
+                }
+            };
+            parsed.push((name, value));
+        }
+        parsed
     };
     let (basic_auth_name, basic_auth_pass) = match matches.opt_str("a") {
         Some(v) => {
@@ -215,6 +345,53 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         }
         None => ("".to_string(), 0),
     };
+    let connect_timeout = match matches.opt_str("connect-timeout") {
+        Some(v) => match v.parse::<f64>() {
+            Ok(sec) => Some(Duration::from_secs_f64(sec)),
+            Err(_) => {
+                println!("invalid connect-timeout: {}\n", v);
+                print_usage(&opts);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let timeout = match matches.opt_str("timeout") {
+        Some(v) => match v.parse::<f64>() {
+            Ok(sec) => Some(Duration::from_secs_f64(sec)),
+            Err(_) => {
+                println!("invalid timeout: {}\n", v);
+                print_usage(&opts);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let duration = match matches.opt_str("z") {
+        Some(v) => match parse_duration(&v) {
+            Some(d) => Some(d),
+            None => {
+                println!("invalid duration: {}\n", v);
+                print_usage(&opts);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let output_format = match matches.opt_str("o") {
+        Some(v) => match v.to_lowercase().as_str() {
+            "text" => OutputFormat::Text,
+            "json" => OutputFormat::Json,
+            "csv" => OutputFormat::Csv,
+            _ => {
+                println!("invalid output format: {}\n", v);
+                print_usage(&opts);
+                process::exit(1);
+            }
+        },
+        None => OutputFormat::Text,
+    };
+    let output_file = matches.opt_str("output");
     let mut opt = BoomOption {
         concurrency: 0,
         num_requests: 0,
@@ -225,6 +402,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         password: basic_auth_pass,
         proxy_host: proxy_host,
         proxy_port: proxy_port,
+        connect_timeout: connect_timeout,
+        timeout: timeout,
+        http2: matches.opt_present("http2"),
+        h2c: matches.opt_present("h2c"),
+        duration: duration,
+        headers: headers,
         mime: Mime::from_str(mime_v.as_str()).unwrap(),
         keepalive: !matches.opt_present("disable-keepalive"),
         compress: !matches.opt_present("disable-compress"),
@@ -242,61 +425,77 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         std::process::exit(1)
     };
 
-    let mut handles = vec![];
-    let mut workers = vec![];
+    // there is no TLS connector, so https (and therefore ALPN-negotiated h2)
+    // cannot connect; reject it up front instead of failing every request.
+    if opt.url.starts_with("https://") {
+        println!("https URLs are not supported (no TLS connector); use http://\n");
+        print_usage(&opts);
+        process::exit(1);
+    }
 
     let client = Arc::new(get_request(&opt));
 
-    // create worker
-    for _ in 0..opt.concurrency {
-        let (worker_tx, worker_rx) = channel::<Option<WorkerOption>>();
-        workers.push(worker_tx.clone());
-        let c = client.clone();
-        // handles.push(thread::spawn(move || exec_worker(&c, worker_rx)));
-        handles.push(thread::spawn(move || {
-            let rt = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap();
-            rt.block_on(async {
-                exec_worker(&c, worker_rx).await
-            });
-        }));
-    }
+    // a single multi-threaded runtime drives every request; `concurrency`
+    // permits bound the number of in-flight requests so a slow server throttles
+    // submission instead of letting the queue grow without bound.
+    let sem = Arc::new(Semaphore::new(opt.concurrency as usize));
+    let report = Arc::new(Mutex::new(Report::new()));
+    let completed = Arc::new(AtomicI64::new(0));
+    let mut handles = vec![];
 
     let t1 = SystemTime::now();
 
-    let report = Arc::new(Mutex::new(Report::new()));
-    // request for attack
-    for cnt in 0..opt.num_requests {
-        let w = WorkerOption {
-            opts: opt.clone(),
-            report: report.clone(),
-        };
-        let offset = ((cnt as i32) % opt.concurrency) as usize;
-        let req = workers[offset].clone();
-        req.send(Some(w)).expect("request.send() error:");
-    }
-
-    // exit for worker
-    for worker in workers {
-        worker.send(None).expect("worker.send(None) error:");
+    match opt.duration {
+        Some(d) => {
+            // run until the deadline: keep acquiring a permit and spawning a
+            // request, blocking on the permit once `concurrency` are in flight.
+            let deadline = Instant::now() + d;
+            while Instant::now() < deadline {
+                let permit = sem.clone().acquire_owned().await.unwrap();
+                let c = client.clone();
+                let o = opt.clone();
+                let r = report.clone();
+                let n = completed.clone();
+                handles.push(tokio::spawn(async move {
+                    b(&c, o, r).await;
+                    n.fetch_add(1, Ordering::Relaxed);
+                    drop(permit);
+                }));
+            }
+        }
+        None => {
+            for _ in 0..opt.num_requests {
+                let permit = sem.clone().acquire_owned().await.unwrap();
+                let c = client.clone();
+                let o = opt.clone();
+                let r = report.clone();
+                let n = completed.clone();
+                handles.push(tokio::spawn(async move {
+                    b(&c, o, r).await;
+                    n.fetch_add(1, Ordering::Relaxed);
+                    drop(permit);
+                }));
+            }
+        }
     }
 
     for handle in handles {
-        handle.join().expect("thread.join() error:");
+        handle.await.expect("task.await error:");
     }
     let t2 = SystemTime::now();
     let duration = t2.duration_since(t1).unwrap();
-    let diff = duration.subsec_micros() as f32;
+    let diff = duration.as_secs_f64() as f32 * 1_000_000.;
 
-    let request_per_seconds = 1000000. * opt.num_requests as f32 / diff;
+    let total_requests = completed.load(Ordering::Relaxed);
+    let request_per_seconds = 1000000. * total_requests as f32 / diff;
 
     {
         let r = report.clone();
         let mut report_mut = (*r).lock().unwrap();
         report_mut.time_exec_total = diff / 1000.;
         report_mut.req_per_sec = request_per_seconds;
+        report_mut.format = output_format;
+        report_mut.output = output_file;
         report_mut.finalize();
     }
 
@@ -1,10 +1,100 @@
 use std::collections::HashMap;
+use std::fs;
 use std::iter;
 
+// Output format selected with `-o`. `Text` is the default human-readable
+// summary; `Json`/`Csv` emit machine-readable reports for CI pipelines.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Csv,
+}
+
+// number of linear sub-buckets per order of magnitude; 2^10 = 1024 gives
+// roughly three significant digits of precision.
+const PRECISION_BITS: u32 = 10;
+
+// An HDR-style latency recorder: values (microseconds) are split into a
+// magnitude bucket and a linear sub-bucket, and the counts live in a flat
+// `Vec<u64>` indexed by `(magnitude << PRECISION_BITS) | sub`. Magnitude 0
+// holds the values below 2^PRECISION_BITS, one per sub-bucket; a larger value
+// has magnitude `floor(log2(value)) - PRECISION_BITS + 1`.
+// Recording is O(1) and memory is bounded by the dynamic range, not the number
+// of samples, so percentiles stay accurate across millions of requests.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl Histogram {
+    fn bucket_index(value: u64) -> usize {
+        let unit = 1u64 << PRECISION_BITS;
+        if value < unit {
+            return value as usize;
+        }
+        let exponent = 63 - value.leading_zeros() as u64; // floor(log2(value))
+        let sub = (value >> (exponent - PRECISION_BITS as u64)) & (unit - 1);
+        (((exponent - PRECISION_BITS as u64 + 1) << PRECISION_BITS) | sub) as usize
+    }
+
+    // lower bound of the values that map to `index`; used as the bucket's
+    // representative value when reporting.
+    fn representative(index: usize) -> u64 {
+        let unit = 1u64 << PRECISION_BITS;
+        let index = index as u64;
+        if index < unit {
+            return index;
+        }
+        let exponent = (index >> PRECISION_BITS) + PRECISION_BITS as u64 - 1;
+        let sub = index & (unit - 1);
+        (unit | sub) << (exponent - PRECISION_BITS as u64)
+    }
+
+    pub fn record(&mut self, value: u64) {
+        let index = Self::bucket_index(value);
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.total += 1;
+    }
+
+    // representative value (microseconds) at percentile `p` in [0, 100].
+    fn value_at_percentile(&self, p: f64) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        let threshold = (p / 100. * self.total as f64).ceil() as u64;
+        let mut acc = 0u64;
+        for (i, &c) in self.counts.iter().enumerate() {
+            acc += c;
+            if acc >= threshold {
+                return Self::representative(i);
+            }
+        }
+        Self::representative(self.counts.len().saturating_sub(1))
+    }
+
+    // non-empty buckets as (representative microseconds, count), ascending.
+    fn occupied(&self) -> Vec<(u64, u64)> {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &c)| c > 0)
+            .map(|(i, &c)| (Self::representative(i), c))
+            .collect()
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Report {
     pub size_total: i64,
     pub size_per_req: i64,
+    pub compressed_total: i64, // on-wire bytes received
+    pub decoded_total: i64, // bytes after content-encoding is decoded
     pub req_per_sec: f32,
     pub time_total: f32, // milliseconds
     pub time_exec_total: f32, // milliseconds
@@ -12,19 +102,36 @@ pub struct Report {
     pub req_num: i32,
     pub results: Vec<(u16, f32)>, // (StatusCode, Milliseconds)
     pub status_num: HashMap<u16, i64>, // HashMap<StatusCode, OkCount>
-    time_slowest: f32,
-    time_fastest: f32,
-    lats: Vec<i32>,
+    pub version_num: HashMap<String, i64>, // HashMap<HttpVersion, Count>
+    pub hist: Histogram,
+    pub format: OutputFormat,
+    pub output: Option<String>,
 }
 
+// percentiles reported in both the text summary and the machine-readable output
+const PERCENTILES: [f64; 8] = [10., 25., 50., 75., 90., 95., 99., 99.9];
+
 impl Report {
     pub fn new() -> Self {
         Report { ..Self::default() }
     }
 
+    // slowest/fastest latency in milliseconds, taken from the histogram's
+    // occupied buckets so mixed status codes don't skew the result (`results`
+    // is sorted by the full (status, latency) tuple). (0, 0) when no request
+    // succeeded far enough to be recorded (e.g. every connection failed).
+    fn slowest_fastest(&self) -> (f32, f32) {
+        let occupied = self.hist.occupied();
+        if occupied.is_empty() {
+            return (0., 0.);
+        }
+        let fastest = occupied.first().unwrap().0 as f32 / 1000.;
+        let slowest = occupied.last().unwrap().0 as f32 / 1000.;
+        (slowest, fastest)
+    }
+
     fn print(&mut self) {
-        let (_, slowest) = self.results[self.results.len() - 1];
-        let (_, fastest) = self.results[0];
+        let (slowest, fastest) = self.slowest_fastest();
         println!("Summary:");
         println!("  Total:        {:.5} s", self.time_exec_total / 1000.);
         println!("  Slowest:      {:.5} s", slowest / 1000.);
@@ -33,19 +140,28 @@ impl Report {
         println!("  Requests/sec: {:.2}", self.req_per_sec);
         println!("  Total data:   {:} bytes", self.size_total);
         println!("  Size/request: {:} bytes", self.size_per_req);
-        println!("");
-
-        for &(_, t) in self.results.iter() {
-            self.lats.push(t as i32);
+        println!("  Transferred:  {:} bytes (on-wire)", self.compressed_total);
+        println!("  Decoded:      {:} bytes", self.decoded_total);
+        if self.compressed_total > 0 {
+            let ratio = self.decoded_total as f64 / self.compressed_total as f64;
+            println!("  Compression:  {:.3}x", ratio);
         }
+        println!("");
 
-        self.time_slowest = slowest;
-        self.time_fastest = fastest;
+        self.print_protocol();
         self.print_status();
         self.print_histogram();
         self.print_latency();
     }
 
+    fn print_protocol(&mut self) {
+        println!("Negotiated protocol:");
+        for (k, v) in self.version_num.iter() {
+            println!("  [{}] {} responses", k, v);
+        }
+        println!("");
+    }
+
     fn print_status(&mut self) {
         println!("Status code distribution:");
         for (k, v) in self.status_num.iter() {
@@ -55,73 +171,155 @@ impl Report {
     }
 
     fn print_histogram(&mut self) {
-        let bc = 10;
-        let mut buckets = vec![0.0; bc+1];
-        let mut counts = vec![0; bc+1];
-        let bs = (self.time_slowest - self.time_fastest) / bc as f32;
-
-        for i in 0..bc {
-            buckets[i] = self.time_fastest + bs * i as f32;
-        }
-        buckets[bc] = self.time_slowest;
-        let mut bi = 0;
-        let mut max = 0;
-        let mut ri = 0;
-        loop {
-            if ri >= self.lats.len() {
-                break;
-            }
-            if self.lats[ri] as f32 <= buckets[bi] {
-                ri += 1;
-                counts[bi] += 1;
-                if max < counts[bi] {
-                    max = counts[bi];
-                }
-            } else if bi < (buckets.len() - 1) {
-                bi += 1;
-            }
-        }
+        let occupied = self.hist.occupied();
+        let max = occupied.iter().map(|&(_, c)| c).max().unwrap_or(0);
         println!("Response time histogram:");
-        for i in 0..buckets.len() {
+        for (value_us, count) in occupied.iter() {
             let mut bar_len = 0;
             if max > 0 {
-                bar_len = counts[i] * 40 / max
+                bar_len = (count * 40 / max) as usize;
             }
             println!("  {:-4.3} [{:-?}]\t|{}",
-                     buckets[i] / 1000.,
-                     counts[i],
+                     *value_us as f64 / 1_000_000.,
+                     count,
                      iter::repeat("*").take(bar_len).collect::<String>());
         }
         println!("");
     }
 
     fn print_latency(&mut self) {
-        let pctls = vec![10, 25, 50, 75, 90, 95, 99];
-        let mut data = vec![0.0; pctls.len()];
-        let mut j = 0;
-        for i in 0..self.lats.len() {
-            if !(i < self.lats.len() && j < pctls.len()) {
-                break;
-            }
-            let current = i * 100 / self.lats.len();
-            if current >= pctls[j] {
-                data[j] = self.lats[i] as f32;
-                j += 1;
+        println!("Latency distribution:");
+        for p in PERCENTILES.iter() {
+            let value_us = self.hist.value_at_percentile(*p);
+            if value_us > 0 {
+                println!("  {}% in {:4.4} secs", p, value_us as f64 / 1_000_000.);
             }
         }
+    }
 
-        println!("Latency distribution:");
-        for i in 0..pctls.len() {
-            if data[i] > 0. {
-                println!("  {}% in {:4.4} secs", pctls[i], data[i] / 1000.);
+    fn to_json(&self) -> String {
+        let (slowest, fastest) = self.slowest_fastest();
+        let mut s = String::new();
+        s.push_str("{\n");
+        s.push_str(&format!("  \"total\": {:.6},\n", self.time_exec_total / 1000.));
+        s.push_str(&format!("  \"slowest\": {:.6},\n", slowest / 1000.));
+        s.push_str(&format!("  \"fastest\": {:.6},\n", fastest / 1000.));
+        s.push_str(&format!("  \"average\": {:.6},\n", self.time_average / 1000.));
+        s.push_str(&format!("  \"requests_per_sec\": {:.2},\n", self.req_per_sec));
+        s.push_str(&format!("  \"total_data\": {},\n", self.size_total));
+        s.push_str(&format!("  \"size_per_request\": {},\n", self.size_per_req));
+        s.push_str(&format!("  \"compressed_total\": {},\n", self.compressed_total));
+        s.push_str(&format!("  \"decoded_total\": {},\n", self.decoded_total));
+
+        let status: Vec<String> = self
+            .status_num
+            .iter()
+            .map(|(k, v)| format!("    \"{}\": {}", k, v))
+            .collect();
+        s.push_str(&format!("  \"status_codes\": {{\n{}\n  }},\n", status.join(",\n")));
+
+        let buckets: Vec<String> = self
+            .hist
+            .occupied()
+            .iter()
+            .map(|(value_us, count)| {
+                format!("    {{ \"latency\": {:.6}, \"count\": {} }}", *value_us as f64 / 1_000_000., count)
+            })
+            .collect();
+        s.push_str(&format!("  \"histogram\": [\n{}\n  ],\n", buckets.join(",\n")));
+
+        let pctls: Vec<String> = PERCENTILES
+            .iter()
+            .map(|p| format!("    \"{}\": {:.6}", p, self.hist.value_at_percentile(*p) as f64 / 1_000_000.))
+            .collect();
+        s.push_str(&format!("  \"percentiles\": {{\n{}\n  }}\n", pctls.join(",\n")));
+        s.push_str("}\n");
+        s
+    }
+
+    fn to_csv(&self) -> String {
+        let mut s = String::new();
+        s.push_str("status,latency_secs\n");
+        for &(status, millisec) in self.results.iter() {
+            s.push_str(&format!("{},{:.6}\n", status, millisec / 1000.));
+        }
+        let (slowest, fastest) = self.slowest_fastest();
+        s.push_str("\nmetric,value\n");
+        s.push_str(&format!("total,{:.6}\n", self.time_exec_total / 1000.));
+        s.push_str(&format!("slowest,{:.6}\n", slowest / 1000.));
+        s.push_str(&format!("fastest,{:.6}\n", fastest / 1000.));
+        s.push_str(&format!("average,{:.6}\n", self.time_average / 1000.));
+        s.push_str(&format!("requests_per_sec,{:.2}\n", self.req_per_sec));
+        s.push_str(&format!("total_data,{}\n", self.size_total));
+        s.push_str(&format!("size_per_request,{}\n", self.size_per_req));
+        s.push_str(&format!("compressed_total,{}\n", self.compressed_total));
+        s.push_str(&format!("decoded_total,{}\n", self.decoded_total));
+        for p in PERCENTILES.iter() {
+            s.push_str(&format!("p{},{:.6}\n", p, self.hist.value_at_percentile(*p) as f64 / 1_000_000.));
+        }
+        s
+    }
+
+    fn emit(&self, content: &str) {
+        match &self.output {
+            Some(path) => {
+                if let Err(e) = fs::write(path, content) {
+                    eprintln!("cannot write report to {}: {}", path, e);
+                }
             }
+            None => print!("{}", content),
         }
     }
 
     pub fn finalize(&mut self) {
         self.results.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        self.time_average = self.time_total / self.req_num as f32;
-        self.size_per_req = self.size_total / self.req_num as i64;
-        self.print();
+        // every request may have failed before producing a result; avoid the
+        // divide-by-zero that would otherwise abort the run at report time.
+        if self.req_num > 0 {
+            self.time_average = self.time_total / self.req_num as f32;
+            self.size_per_req = self.size_total / self.req_num as i64;
+        }
+        match self.format {
+            OutputFormat::Text => self.print(),
+            OutputFormat::Json => {
+                let out = self.to_json();
+                self.emit(&out);
+            }
+            OutputFormat::Csv => {
+                let out = self.to_csv();
+                self.emit(&out);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn representative_brackets_value() {
+        // the representative (lower bound) of a value's bucket must not exceed
+        // the value, and the next bucket's lower bound must exceed it.
+        for &v in &[0u64, 1, 7, 1023, 1024, 1025, 5000, 1_000_000, 123_456_789] {
+            let idx = Histogram::bucket_index(v);
+            let lo = Histogram::representative(idx);
+            let hi = Histogram::representative(idx + 1);
+            assert!(lo <= v, "representative {} should be <= value {}", lo, v);
+            assert!(hi > v, "next bucket {} should be > value {}", hi, v);
+        }
+    }
+
+    #[test]
+    fn percentiles_over_fixed_sample() {
+        let mut h = Histogram::default();
+        // 1..=100 each fall in their own linear sub-bucket (< 1024), so the
+        // reported percentile value is exact.
+        for v in 1..=100u64 {
+            h.record(v);
+        }
+        assert_eq!(h.value_at_percentile(50.), 50);
+        assert_eq!(h.value_at_percentile(99.), 99);
+        assert_eq!(h.value_at_percentile(100.), 100);
     }
 }